@@ -10,14 +10,24 @@ use std::iter::once;
 use std::iter::Peekable;
 use std::os::windows::ffi::OsStrExt;
 use std::ptr::null_mut;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::Ordering;
 
-const USAGE: &str = r#"Usage: $BIN_NAME [-aV] [-o file] [--append] [--output file]
-       [--help] [--version] command [arg...]"#;
+const USAGE: &str = r#"Usage: $BIN_NAME [-aVv] [-o file] [-t duration] [-f format]
+       [--append] [--output file] [--timeout duration] [--verbose]
+       [--format format] [--help] [--version] command [arg...]"#;
 const BIN_NAME: &str = env!("CARGO_PKG_NAME");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const EXIT_ERROR: i32 = 2;
+const EXIT_TIMEOUT: i32 = 124;
+const EXIT_INTERRUPTED: i32 = 130;
 
-fn ignore<T>(_: T) {}
+// The console control handler runs on a separate OS thread, so the job/port
+// handles it needs to tear everything down live in process-lifetime statics.
+static CTRL_HJOB: AtomicPtr<std::ffi::c_void> = AtomicPtr::new(null_mut());
+static CTRL_HIOCP: AtomicPtr<std::ffi::c_void> = AtomicPtr::new(null_mut());
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 fn usage(code: i32) -> ! {
     eprintln!("{}", USAGE.replace("$BIN_NAME", BIN_NAME));
@@ -96,6 +106,26 @@ struct Times {
     kernel: f64,
 }
 
+enum WaitResult {
+    Completed,
+    TimedOut,
+}
+
+struct ResourceUsage {
+    // peak working set of the single heaviest process in the job, in bytes
+    peak_process_memory: u64,
+
+    // peak committed memory across the whole job, in bytes
+    peak_job_memory: u64,
+
+    read_operations: u64,
+    write_operations: u64,
+    other_operations: u64,
+    read_bytes: u64,
+    write_bytes: u64,
+    other_bytes: u64,
+}
+
 unsafe fn _0<T>() -> T {
     std::mem::zeroed()
 }
@@ -111,19 +141,21 @@ fn void_ptr<T, R>(value: &mut T) -> *mut R {
 fn win32_get_perf_counter() -> f64 {
     use winapi::um::profileapi::QueryPerformanceCounter;
 
-    let mut res = 0.0;
+    // The API fills a LARGE_INTEGER; read it as an integer and then widen,
+    // rather than reinterpreting its bit pattern as a float.
+    let mut res: i64 = 0;
     let ret = unsafe { QueryPerformanceCounter(void_ptr(&mut res)) };
-    win32_assert(ret, "QueryPerformanceFrequency");
-    res
+    win32_assert(ret, "QueryPerformanceCounter");
+    res as f64
 }
 
 fn win32_get_perf_freq() -> f64 {
     use winapi::um::profileapi::QueryPerformanceFrequency;
 
-    let mut res = 0.0;
+    let mut res: i64 = 0;
     let ret = unsafe { QueryPerformanceFrequency(void_ptr(&mut res)) };
     win32_assert(ret, "QueryPerformanceFrequency");
-    res
+    res as f64
 }
 
 fn convert_utf16(s: &str) -> Vec<u16> {
@@ -190,6 +222,51 @@ fn win32_create_job() -> JobDescr {
     JobDescr { hjob, hiocp }
 }
 
+fn win32_get_process_exit_code(process: &ProcessDescr) -> i32 {
+    use winapi::um::processthreadsapi::GetExitCodeProcess;
+
+    let mut code = 0u32;
+    let ret = unsafe { GetExitCodeProcess(process.0, ptr(&mut code)) };
+    win32_assert(ret, "GetExitCodeProcess");
+    code as i32
+}
+
+unsafe extern "system" fn ctrl_handler(ctrl_type: winapi::shared::minwindef::DWORD) -> BOOL {
+    use winapi::um::ioapiset::PostQueuedCompletionStatus;
+    use winapi::um::jobapi2::TerminateJobObject;
+    use winapi::um::wincon::CTRL_BREAK_EVENT;
+    use winapi::um::wincon::CTRL_CLOSE_EVENT;
+    use winapi::um::wincon::CTRL_C_EVENT;
+
+    match ctrl_type {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT | CTRL_CLOSE_EVENT => {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            // Killing the job takes the whole attached process tree down.
+            let hjob = CTRL_HJOB.load(Ordering::SeqCst) as HANDLE;
+            if !hjob.is_null() {
+                TerminateJobObject(hjob, EXIT_INTERRUPTED as u32);
+            }
+            // Nudge the completion port so the main thread leaves its wait and
+            // gets a chance to flush the accumulated timing report.
+            let hiocp = CTRL_HIOCP.load(Ordering::SeqCst) as HANDLE;
+            if !hiocp.is_null() {
+                PostQueuedCompletionStatus(hiocp, 0, 0, null_mut());
+            }
+            TRUE
+        }
+        _ => FALSE,
+    }
+}
+
+fn win32_install_ctrl_handler(job: &JobDescr) {
+    use winapi::um::consoleapi::SetConsoleCtrlHandler;
+
+    CTRL_HJOB.store(job.hjob as *mut _, Ordering::SeqCst);
+    CTRL_HIOCP.store(job.hiocp as *mut _, Ordering::SeqCst);
+    let res = unsafe { SetConsoleCtrlHandler(Some(ctrl_handler), TRUE) };
+    win32_assert(res, "SetConsoleCtrlHandler");
+}
+
 fn win32_attach_process_to_job(process: &ProcessDescr, job: &JobDescr) {
     use winapi::um::jobapi2::AssignProcessToJobObject;
     let res = unsafe { AssignProcessToJobObject(job.hjob, process.0) };
@@ -197,11 +274,72 @@ fn win32_attach_process_to_job(process: &ProcessDescr, job: &JobDescr) {
 }
 
 impl JobDescr {
-    fn wait_for_job_completion(&self) {
+    fn wait_for_job_completion(&self, timeout: Option<f64>, freq: f64) -> WaitResult {
         use winapi::um::ioapiset::GetQueuedCompletionStatus;
+        use winapi::um::jobapi2::TerminateJobObject;
         use winapi::um::winbase::INFINITE;
         use winapi::um::winnt::JOB_OBJECT_MSG_ACTIVE_PROCESS_ZERO;
 
+        // Absolute deadline expressed in performance-counter ticks. Tracking it
+        // this way means a spurious wakeup only shortens the remaining wait
+        // rather than restarting the full timeout.
+        let deadline = timeout.map(|t| win32_get_perf_counter() + t * freq);
+
+        unsafe {
+            let mut completion_code = _0();
+            let mut completion_key = _0();
+            let mut overlapped = _0();
+            loop {
+                // Check the deadline up front: a job spawning children fast
+                // keeps posting non-ZERO packets on the TRUE path, which would
+                // otherwise starve a deadline only tested on the FALSE branch.
+                if let Some(deadline) = deadline {
+                    if win32_get_perf_counter() >= deadline {
+                        TerminateJobObject(self.hjob, EXIT_TIMEOUT as u32);
+                        self.drain_completion_port();
+                        return WaitResult::TimedOut;
+                    }
+                }
+                let wait_ms = match deadline {
+                    None => INFINITE,
+                    Some(deadline) => {
+                        let remaining = (deadline - win32_get_perf_counter()) / freq;
+                        if remaining <= 0.0 {
+                            0
+                        } else {
+                            (remaining * 1000.0).ceil() as u32
+                        }
+                    }
+                };
+                let ret = GetQueuedCompletionStatus(
+                    self.hiocp,
+                    ptr(&mut completion_code),
+                    ptr(&mut completion_key),
+                    ptr(&mut overlapped),
+                    wait_ms,
+                );
+                // A console interrupt terminates the job and posts a wakeup;
+                // stop waiting so main can report and exit with code 130.
+                if INTERRUPTED.load(Ordering::SeqCst) {
+                    return WaitResult::Completed;
+                }
+                if ret == TRUE
+                    && completion_key as HANDLE == self.hjob
+                    && completion_code == JOB_OBJECT_MSG_ACTIVE_PROCESS_ZERO
+                {
+                    return WaitResult::Completed;
+                }
+            }
+        }
+    }
+
+    // Consume the packets already queued on the completion port after the job
+    // is torn down. This does not block: it stops at the job's
+    // active-process-zero packet or as soon as the port is empty.
+    fn drain_completion_port(&self) {
+        use winapi::um::ioapiset::GetQueuedCompletionStatus;
+        use winapi::um::winnt::JOB_OBJECT_MSG_ACTIVE_PROCESS_ZERO;
+
         unsafe {
             let mut completion_code = _0();
             let mut completion_key = _0();
@@ -211,7 +349,7 @@ impl JobDescr {
                 ptr(&mut completion_code),
                 ptr(&mut completion_key),
                 ptr(&mut overlapped),
-                INFINITE,
+                0,
             ) == TRUE
                 && !(completion_key as HANDLE == self.hjob
                     && completion_code == JOB_OBJECT_MSG_ACTIVE_PROCESS_ZERO)
@@ -246,6 +384,49 @@ impl JobDescr {
             kernel: to_seconds(info.TotalKernelTime),
         }
     }
+
+    fn get_resource_usage(&self) -> ResourceUsage {
+        use winapi::um::jobapi2::QueryInformationJobObject;
+        use winapi::um::winnt::JobObjectBasicAndIoAccountingInformation;
+        use winapi::um::winnt::JobObjectExtendedLimitInformation;
+        use winapi::um::winnt::JOBOBJECT_BASIC_AND_IO_ACCOUNTING_INFORMATION;
+        use winapi::um::winnt::JOBOBJECT_EXTENDED_LIMIT_INFORMATION;
+
+        let mut io_info: JOBOBJECT_BASIC_AND_IO_ACCOUNTING_INFORMATION;
+        let mut limit_info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION;
+        unsafe {
+            io_info = _0();
+            let ret = QueryInformationJobObject(
+                self.hjob,
+                JobObjectBasicAndIoAccountingInformation,
+                void_ptr(&mut io_info),
+                std::mem::size_of::<JOBOBJECT_BASIC_AND_IO_ACCOUNTING_INFORMATION>() as u32,
+                null_mut(),
+            );
+            win32_assert(ret, "QueryInformationJobObject");
+
+            limit_info = _0();
+            let ret = QueryInformationJobObject(
+                self.hjob,
+                JobObjectExtendedLimitInformation,
+                void_ptr(&mut limit_info),
+                std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+                null_mut(),
+            );
+            win32_assert(ret, "QueryInformationJobObject");
+        };
+        let io = io_info.IoInfo;
+        ResourceUsage {
+            peak_process_memory: limit_info.PeakProcessMemoryUsed as u64,
+            peak_job_memory: limit_info.PeakJobMemoryUsed as u64,
+            read_operations: io.ReadOperationCount,
+            write_operations: io.WriteOperationCount,
+            other_operations: io.OtherOperationCount,
+            read_bytes: io.ReadTransferCount,
+            write_bytes: io.WriteTransferCount,
+            other_bytes: io.OtherTransferCount,
+        }
+    }
 }
 
 fn print_duration(f: &mut impl std::io::Write, name: &'static str, seconds: f64) {
@@ -256,6 +437,83 @@ fn print_duration(f: &mut impl std::io::Write, name: &'static str, seconds: f64)
     }
 }
 
+fn print_resource_usage(f: &mut impl std::io::Write, usage: &ResourceUsage) {
+    let max_rss_kb = usage.peak_process_memory.max(usage.peak_job_memory) / 1024;
+    let write = |f: &mut dyn std::io::Write, label: &str, value: u64| {
+        if let Err(e) = writeln!(f, "\t{}: {}", label, value) {
+            die_io_error("failed to write", e);
+        }
+    };
+    write(f, "maximum resident set size (kbytes)", max_rss_kb);
+    write(f, "file system inputs", usage.read_operations);
+    write(f, "file system outputs", usage.write_operations);
+    write(f, "other operations", usage.other_operations);
+    write(f, "bytes read", usage.read_bytes);
+    write(f, "bytes written", usage.write_bytes);
+    write(f, "other bytes", usage.other_bytes);
+}
+
+// Metrics available to a `--format` template.
+struct Metrics {
+    wall: f64,
+    user: f64,
+    kernel: f64,
+    exit_code: i32,
+    usage: ResourceUsage,
+}
+
+// Render a `time(1)`-style format template against the collected metrics.
+// Recognized specifiers: %e %U %S %P %x %M %I %O %%, plus \n and \t escapes.
+fn render_format(f: &mut impl std::io::Write, template: &str, m: &Metrics) {
+    let mut out = String::new();
+    let mut chars = template.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '%' => match chars.next() {
+                Some('e') => out.push_str(&format!("{:.3}", m.wall)),
+                Some('U') => out.push_str(&format!("{:.3}", m.user)),
+                Some('S') => out.push_str(&format!("{:.3}", m.kernel)),
+                Some('P') => {
+                    let cpu = if m.wall > 0.0 {
+                        100.0 * (m.user + m.kernel) / m.wall
+                    } else {
+                        0.0
+                    };
+                    out.push_str(&format!("{:.0}%", cpu));
+                }
+                Some('x') => out.push_str(&m.exit_code.to_string()),
+                Some('M') => {
+                    let kb = m.usage.peak_process_memory.max(m.usage.peak_job_memory) / 1024;
+                    out.push_str(&kb.to_string());
+                }
+                Some('I') => out.push_str(&m.usage.read_operations.to_string()),
+                Some('O') => out.push_str(&m.usage.write_operations.to_string()),
+                Some('%') => out.push('%'),
+                Some(other) => {
+                    out.push('%');
+                    out.push(other);
+                }
+                None => out.push('%'),
+            },
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some('\\') => out.push('\\'),
+                Some(other) => {
+                    out.push('\\');
+                    out.push(other);
+                }
+                None => out.push('\\'),
+            },
+            ch => out.push(ch),
+        }
+    }
+    out.push('\n');
+    if let Err(e) = write!(f, "{}", out) {
+        die_io_error("failed to write", e);
+    }
+}
+
 fn open_file(path: &std::path::Path, append: bool) -> std::io::Result<std::fs::File> {
     std::fs::OpenOptions::new()
         .truncate(!append)
@@ -269,6 +527,63 @@ fn open_file(path: &std::path::Path, append: bool) -> std::io::Result<std::fs::F
 struct Opts {
     ofile: Option<String>,
     append: bool,
+    verbose: bool,
+    timeout: Option<f64>,
+    format: Option<String>,
+}
+
+fn parse_format(opts: &mut Opts, args: &mut Peekable<impl Iterator<Item = String>>) -> bool {
+    let arg = args.next().unwrap();
+    match args.next() {
+        None => {
+            missing_arg(arg);
+        }
+        Some(fmt) => {
+            opts.format = Some(fmt);
+            true
+        }
+    }
+}
+
+// Parse a duration like `30`, `30s`, `500ms`, `5m` or `1h` into seconds.
+fn parse_duration(arg: &str, spec: &str) -> f64 {
+    let (number, scale) = if let Some(n) = spec.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = spec.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = spec.strip_suffix('m') {
+        (n, 60.0)
+    } else if let Some(n) = spec.strip_suffix('h') {
+        (n, 3600.0)
+    } else {
+        (spec, 1.0)
+    };
+    match number.parse::<f64>() {
+        Ok(value) if value >= 0.0 => value * scale,
+        _ => {
+            eprintln!("invalid duration: '{}'", arg);
+            usage(EXIT_ERROR);
+        }
+    }
+}
+
+fn parse_timeout(opts: &mut Opts, args: &mut Peekable<impl Iterator<Item = String>>) -> bool {
+    let arg = args.next().unwrap();
+    match args.next() {
+        None => {
+            missing_arg(arg);
+        }
+        Some(spec) => {
+            opts.timeout = Some(parse_duration(&arg, &spec));
+            true
+        }
+    }
+}
+
+fn parse_verbose(opts: &mut Opts, args: &mut Peekable<impl Iterator<Item = String>>) -> bool {
+    args.next();
+    opts.verbose = true;
+    true
 }
 
 fn parse_output(opts: &mut Opts, args: &mut Peekable<impl Iterator<Item = String>>) -> bool {
@@ -299,16 +614,27 @@ fn parse_arg(opts: &mut Opts, args: &mut Peekable<impl Iterator<Item = String>>)
                 "--help" => usage(0),
                 "--version" => show_version(),
                 "--append" => parse_append(opts, args),
+                "--verbose" => parse_verbose(opts, args),
+                "--timeout" => parse_timeout(opts, args),
+                "--format" => parse_format(opts, args),
                 arg => invalid_opt(arg),
             }
         } else if arg.starts_with("-") {
             if arg == "-o" {
                 parse_output(opts, args)
+            } else if arg == "-t" {
+                parse_timeout(opts, args)
+            } else if arg == "-f" {
+                parse_format(opts, args)
             } else {
+                // Consume the cluster token once; each flag in it only toggles
+                // state, so the arms must not advance the iterator themselves.
+                args.next();
                 for ch in arg.chars().skip(1) {
                     match ch {
                         'V' => show_version(),
-                        'a' => ignore(parse_append(opts, args)),
+                        'a' => opts.append = true,
+                        'v' => opts.verbose = true,
                         ch => invalid_opt(ch),
                     }
                 }
@@ -343,19 +669,56 @@ fn main() {
     let job = win32_create_job();
     let (process, thread) = win32_create_suspended_process(&args);
     win32_attach_process_to_job(&process, &job);
-    drop(process);
+    win32_install_ctrl_handler(&job);
 
     let wall0 = win32_get_perf_counter();
     thread.resume();
     drop(thread);
-    job.wait_for_job_completion();
+    let wait_result = job.wait_for_job_completion(opts.timeout, freq);
     let wall1 = win32_get_perf_counter();
 
     let wall = (wall1 - wall0) / freq;
 
     let job_times = job.get_job_times();
 
-    print_duration(&mut w, "real", wall);
-    print_duration(&mut w, "user", job_times.user);
-    print_duration(&mut w, "sys", job_times.kernel);
+    let code = if INTERRUPTED.load(Ordering::SeqCst) {
+        EXIT_INTERRUPTED
+    } else {
+        match wait_result {
+            // The job was force-terminated, so report the timeout status
+            // instead of reading the child's own code with GetExitCodeProcess.
+            WaitResult::TimedOut => EXIT_TIMEOUT,
+            WaitResult::Completed => win32_get_process_exit_code(&process),
+        }
+    };
+
+    match opts.format {
+        Some(template) => {
+            let metrics = Metrics {
+                wall,
+                user: job_times.user,
+                kernel: job_times.kernel,
+                exit_code: code,
+                usage: job.get_resource_usage(),
+            };
+            render_format(&mut w, &template, &metrics);
+        }
+        None => {
+            print_duration(&mut w, "real", wall);
+            print_duration(&mut w, "user", job_times.user);
+            print_duration(&mut w, "sys", job_times.kernel);
+
+            if opts.verbose {
+                let usage = job.get_resource_usage();
+                print_resource_usage(&mut w, &usage);
+            }
+        }
+    }
+
+    // Flush the timing report before we hand our exit status over to the child.
+    if let Err(e) = w.flush() {
+        die_io_error("failed to write", e);
+    }
+
+    std::process::exit(code);
 }